@@ -1,130 +1,971 @@
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::sync::{Arc, Mutex};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
+use crossbeam_channel::{bounded, Sender};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use osmpbf::{BlobDecode, BlobReader, DenseNode, Element, Node, Relation, Way};
+use osmpbf::{BlobDecode, BlobReader, Element};
 use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 
+/// Errors produced by the tool. The IO variants carry the offending output
+/// path so a failing worker can report exactly which file it was writing.
 #[derive(Debug)]
-struct ElementSink {
-    writer: GzEncoder<BufWriter<File>>,
-    num_elements: u64,
-    filenum: Arc<Mutex<u64>>,
+enum Osm2GzipError {
+    Create { path: PathBuf, source: io::Error },
+    Write { path: PathBuf, source: io::Error },
+    Finish { path: PathBuf, source: io::Error },
+    Osm(osmpbf::Error),
+    ThreadPool(rayon::ThreadPoolBuildError),
+    Pipeline(String),
 }
 
-impl ElementSink {
-    const MAX_ELEMENTS_COUNT: u64 = 100_000;
+impl std::fmt::Display for Osm2GzipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Osm2GzipError::Create { path, source } => {
+                write!(f, "failed to create {}: {}", path.display(), source)
+            }
+            Osm2GzipError::Write { path, source } => {
+                write!(f, "failed to write {}: {}", path.display(), source)
+            }
+            Osm2GzipError::Finish { path, source } => {
+                write!(f, "failed to finish {}: {}", path.display(), source)
+            }
+            Osm2GzipError::Osm(err) => write!(f, "OSM PBF error: {}", err),
+            Osm2GzipError::ThreadPool(err) => write!(f, "failed to build thread pool: {}", err),
+            Osm2GzipError::Pipeline(msg) => write!(f, "{}", msg),
+        }
+    }
+}
 
-    fn new(filenum: Arc<Mutex<u64>>) -> Result<Self, std::io::Error> {
-        let f = File::create(Self::new_file_path(&filenum))?;
-        let writer = GzEncoder::new(BufWriter::new(f), Compression::fast());
+impl std::error::Error for Osm2GzipError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Osm2GzipError::Create { source, .. }
+            | Osm2GzipError::Write { source, .. }
+            | Osm2GzipError::Finish { source, .. } => Some(source),
+            Osm2GzipError::Osm(err) => Some(err),
+            Osm2GzipError::ThreadPool(err) => Some(err),
+            Osm2GzipError::Pipeline(_) => None,
+        }
+    }
+}
 
-        Ok(ElementSink {
-            writer,
-            num_elements: 0,
-            filenum,
-        })
+impl From<osmpbf::Error> for Osm2GzipError {
+    fn from(err: osmpbf::Error) -> Self {
+        Osm2GzipError::Osm(err)
     }
+}
+
+impl From<rayon::ThreadPoolBuildError> for Osm2GzipError {
+    fn from(err: rayon::ThreadPoolBuildError) -> Self {
+        Osm2GzipError::ThreadPool(err)
+    }
+}
+
+type Result<T> = std::result::Result<T, Osm2GzipError>;
+
+/// Lock a mutex, recovering the guard if a previous holder panicked instead of
+/// propagating the poison and aborting every other worker.
+fn lock_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
 
-    fn increment_and_cycle(&mut self) -> Result<(), std::io::Error> {
-        self.num_elements += 1;
-        if self.num_elements >= Self::MAX_ELEMENTS_COUNT {
-            let f = File::create(Self::new_file_path(&self.filenum))?;
-            let mut writer = GzEncoder::new(BufWriter::new(f), Compression::fast());
-            std::mem::swap(&mut writer, &mut self.writer);
-            writer.finish()?.flush()?;
-            self.num_elements = 0;
+/// Compression codec used for the output chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Gzip,
+    Zstd,
+    Brotli,
+    Lz4,
+}
+
+impl Codec {
+    fn parse(name: &str) -> Option<Codec> {
+        match name {
+            "gzip" | "gz" => Some(Codec::Gzip),
+            "zstd" | "zst" => Some(Codec::Zstd),
+            "brotli" | "br" => Some(Codec::Brotli),
+            "lz4" => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    /// File extension (without leading dot) for this codec.
+    fn extension(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gz",
+            Codec::Zstd => "zst",
+            Codec::Brotli => "br",
+            Codec::Lz4 => "lz4",
+        }
+    }
+
+    /// Inclusive range of numeric levels accepted for `--level`, so
+    /// out-of-range values are rejected rather than silently clamped by the
+    /// backend.
+    fn level_range(self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Codec::Gzip => 0..=9,
+            Codec::Zstd => 0..=22,
+            Codec::Brotli => 0..=11,
+            Codec::Lz4 => 0..=16,
+        }
+    }
+}
+
+/// Codec plus optional numeric level. A missing level keeps each codec's
+/// default (for gzip that is `Compression::fast()`, the historic behavior).
+#[derive(Debug, Clone, Copy)]
+struct CompressionConfig {
+    codec: Codec,
+    level: Option<u32>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            codec: Codec::Gzip,
+            level: None,
+        }
+    }
+}
+
+/// A writer backend that compresses with the configured codec and can be
+/// finished cleanly to flush the trailing codec footer.
+enum Encoder {
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+    Brotli(brotli::CompressorWriter<BufWriter<File>>),
+    Lz4(lz4::Encoder<BufWriter<File>>),
+}
+
+impl Encoder {
+    fn new(file: File, config: CompressionConfig) -> io::Result<Self> {
+        let buf = BufWriter::new(file);
+        let encoder = match config.codec {
+            Codec::Gzip => {
+                let level = config
+                    .level
+                    .map(Compression::new)
+                    .unwrap_or_else(Compression::fast);
+                Encoder::Gzip(GzEncoder::new(buf, level))
+            }
+            Codec::Zstd => {
+                let level = config.level.unwrap_or(0) as i32;
+                Encoder::Zstd(zstd::stream::write::Encoder::new(buf, level)?)
+            }
+            Codec::Brotli => {
+                let quality = config.level.unwrap_or(9);
+                Encoder::Brotli(brotli::CompressorWriter::new(buf, 4096, quality, 22))
+            }
+            Codec::Lz4 => {
+                let level = config.level.unwrap_or(4);
+                Encoder::Lz4(lz4::EncoderBuilder::new().level(level).build(buf)?)
+            }
+        };
+        Ok(encoder)
+    }
+
+    /// Flush the codec footer and the underlying file.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(w) => {
+                w.finish()?.flush()?;
+            }
+            Encoder::Zstd(w) => {
+                w.finish()?.flush()?;
+            }
+            Encoder::Brotli(w) => {
+                // `flush` alone only emits a flush block; the stream is
+                // finalized in `Drop`, which swallows IO errors. Finalize
+                // explicitly via `into_inner` and flush the `BufWriter<File>`
+                // so a failed final write is propagated like the other arms.
+                let mut inner = w.into_inner();
+                inner.flush()?;
+            }
+            Encoder::Lz4(w) => {
+                let (mut inner, result) = w.finish();
+                result?;
+                inner.flush()?;
+            }
         }
         Ok(())
     }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(w) => w.write(buf),
+            Encoder::Zstd(w) => w.write(buf),
+            Encoder::Brotli(w) => w.write(buf),
+            Encoder::Lz4(w) => w.write(buf),
+        }
+    }
 
-    fn new_file_path(filenum: &Arc<Mutex<u64>>) -> String {
-        let mut num = filenum.lock().unwrap();
-        let path = format!("elements_{:05}.txt.gz", num);
-        *num += 1;
-        path
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(w) => w.flush(),
+            Encoder::Zstd(w) => w.flush(),
+            Encoder::Brotli(w) => w.flush(),
+            Encoder::Lz4(w) => w.flush(),
+        }
     }
+}
 
-    fn add_node(&mut self, node: &Node) -> Result<(), std::io::Error> {
-        writeln!(self.writer, "node {}", node.id())?;
-        self.increment_and_cycle()
+/// Fixed 256-entry Gear table for the rolling hash. Generated with a constant
+/// xorshift so the boundaries are reproducible across runs and machines.
+const GEAR: [u64; 256] = build_gear_table();
+
+const fn build_gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+    let mut i = 0;
+    while i < 256 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        table[i] = state;
+        i += 1;
     }
+    table
+}
+
+/// Parameters for FastCDC-style content-defined chunking.
+#[derive(Debug, Clone, Copy)]
+struct ChunkerConfig {
+    min_size: u64,
+    avg_size: u64,
+    max_size: u64,
+    mask_s: u64,
+    mask_l: u64,
+}
 
-    fn add_dense_node(&mut self, node: &DenseNode) -> Result<(), std::io::Error> {
-        writeln!(self.writer, "node {}", node.id())?;
-        self.increment_and_cycle()
+impl ChunkerConfig {
+    /// Derive the size bounds and normalized masks from a target average size
+    /// (rounded down to a power of two for the mask bit counts).
+    fn new(avg_size: u64) -> Self {
+        let avg_bits = 63 - avg_size.leading_zeros();
+        ChunkerConfig {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+            mask_s: (1u64 << (avg_bits + 2)) - 1,
+            mask_l: (1u64 << (avg_bits - 2)) - 1,
+        }
     }
+}
 
-    fn add_way(&mut self, way: &Way) -> Result<(), std::io::Error> {
-        writeln!(self.writer, "way {}", way.id())?;
-        self.increment_and_cycle()
+/// Rolling Gear hash that decides where to break the serialized byte stream.
+#[derive(Debug)]
+struct GearChunker {
+    config: ChunkerConfig,
+    h: u64,
+    bytes_since_cut: u64,
+}
+
+impl GearChunker {
+    fn new(config: ChunkerConfig) -> Self {
+        GearChunker {
+            config,
+            h: 0,
+            bytes_since_cut: 0,
+        }
     }
 
-    fn add_relation(&mut self, relation: &Relation) -> Result<(), std::io::Error> {
-        writeln!(self.writer, "relation {}", relation.id())?;
-        self.increment_and_cycle()
+    /// Scan a byte slice and return the absolute offsets (within `bytes`) just
+    /// past each content-defined boundary. Internal state resets at each cut,
+    /// so identical byte runs always break at the same place.
+    fn scan(&mut self, bytes: &[u8]) -> Vec<usize> {
+        let mut cuts = Vec::new();
+        for (i, &b) in bytes.iter().enumerate() {
+            self.h = (self.h << 1).wrapping_add(GEAR[b as usize]);
+            self.bytes_since_cut += 1;
+            if self.bytes_since_cut < self.config.min_size {
+                continue;
+            }
+            let boundary = self.bytes_since_cut >= self.config.max_size || {
+                let mask = if self.bytes_since_cut < self.config.avg_size {
+                    self.config.mask_s
+                } else {
+                    self.config.mask_l
+                };
+                self.h & mask == 0
+            };
+            if boundary {
+                cuts.push(i + 1);
+                self.reset();
+            }
+        }
+        cuts
+    }
+
+    fn reset(&mut self) {
+        self.h = 0;
+        self.bytes_since_cut = 0;
     }
 }
 
-fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        println!("Need *.osm.pbf file as first argument.");
-        return Ok(());
+/// How output files are rotated.
+#[derive(Debug, Clone, Copy)]
+enum ChunkingMode {
+    /// Rotate once a sink holds at least `MAX_ELEMENTS_COUNT` elements. The
+    /// check runs after each whole decoded block, so a file can hold more.
+    Count,
+    /// Rotate at content-defined boundaries computed from the byte stream.
+    ContentDefined(ChunkerConfig),
+}
+
+impl Default for ChunkingMode {
+    fn default() -> Self {
+        ChunkingMode::Count
+    }
+}
+
+/// Per-sink rotation state derived from the selected [`ChunkingMode`].
+#[derive(Debug)]
+enum Chunker {
+    Count(u64),
+    ContentDefined(GearChunker),
+}
+
+impl Chunker {
+    fn from_mode(mode: ChunkingMode) -> Self {
+        match mode {
+            ChunkingMode::Count => Chunker::Count(0),
+            ChunkingMode::ContentDefined(config) => {
+                Chunker::ContentDefined(GearChunker::new(config))
+            }
+        }
+    }
+}
+
+/// One logical chunk in emission order, pointing at the file that holds its
+/// (possibly shared) compressed bytes.
+#[derive(Debug)]
+struct ManifestEntry {
+    seq: u64,
+    hash: blake3::Hash,
+    filename: String,
+}
+
+/// Content-addressed index plus the ordered manifest. Shared across workers;
+/// the per-shard locking in [`DashMap`] keeps writes of distinct chunks from
+/// serializing behind a single mutex.
+///
+/// Chunk hashes are reproducible for the same input and the same number of
+/// writer threads: blobs are routed to a fixed partition by file index and
+/// each sink feeds its chunker in index order (see [`Batch`]). The index lives
+/// in memory for a single run, so a repeated chunk within one input is written
+/// once and every later copy becomes a manifest reference.
+#[derive(Debug, Default)]
+struct Dedup {
+    index: DashMap<blake3::Hash, String>,
+    manifest: Mutex<Vec<ManifestEntry>>,
+}
+
+/// Owns file numbering and the optional dedup index. Every sink emits its
+/// completed chunks through the shared instance. Without dedup the only
+/// cross-thread state on the hot path is an atomic counter for file numbering;
+/// with dedup the sharded index, a sequence counter and the manifest mutex are
+/// shared as well.
+#[derive(Debug)]
+struct ChunkWriter {
+    compression: CompressionConfig,
+    filenum: AtomicU64,
+    seq: AtomicU64,
+    dedup: Option<Dedup>,
+}
+
+impl ChunkWriter {
+    fn new(compression: CompressionConfig, dedup: bool) -> Self {
+        ChunkWriter {
+            compression,
+            filenum: AtomicU64::new(0),
+            seq: AtomicU64::new(0),
+            dedup: dedup.then(Dedup::default),
+        }
+    }
+
+    fn next_filename(&self) -> String {
+        let num = self.filenum.fetch_add(1, Ordering::Relaxed);
+        format!(
+            "elements_{:05}.txt.{}",
+            num,
+            self.compression.codec.extension()
+        )
+    }
+
+    /// Write one chunk to disk and report the resulting compressed size.
+    fn write_file(&self, filename: &str, bytes: &[u8]) -> Result<u64> {
+        let path = PathBuf::from(filename);
+        let file = File::create(&path).map_err(|source| Osm2GzipError::Create {
+            path: path.clone(),
+            source,
+        })?;
+        let mut encoder = Encoder::new(file, self.compression).map_err(|source| {
+            Osm2GzipError::Write {
+                path: path.clone(),
+                source,
+            }
+        })?;
+        encoder
+            .write_all(bytes)
+            .map_err(|source| Osm2GzipError::Write {
+                path: path.clone(),
+                source,
+            })?;
+        encoder.finish().map_err(|source| Osm2GzipError::Finish {
+            path: path.clone(),
+            source,
+        })?;
+        let len = std::fs::metadata(&path)
+            .map_err(|source| Osm2GzipError::Write {
+                path: path.clone(),
+                source,
+            })?
+            .len();
+        Ok(len)
+    }
+
+    /// Persist one completed chunk. With dedup enabled a new strong hash is
+    /// written once and later identical chunks only add a manifest reference.
+    /// Returns the logical uncompressed size (counted for every chunk, so it
+    /// matches the per-type element counts) and the compressed bytes actually
+    /// written to disk (zero when a duplicate chunk is skipped).
+    fn emit_chunk(&self, bytes: &[u8]) -> Result<(u64, u64)> {
+        let dedup = match &self.dedup {
+            None => {
+                let filename = self.next_filename();
+                let compressed = self.write_file(&filename, bytes)?;
+                return Ok((bytes.len() as u64, compressed));
+            }
+            Some(dedup) => dedup,
+        };
+
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let hash = blake3::hash(bytes);
+        // Reserve the filename under the shard lock, then drop the guard before
+        // compressing and writing so distinct chunks that happen to share a
+        // DashMap shard don't serialize behind each other's disk IO.
+        let (filename, is_new) = match dedup.index.entry(hash) {
+            Entry::Occupied(existing) => (existing.get().clone(), false),
+            Entry::Vacant(slot) => {
+                let filename = self.next_filename();
+                slot.insert(filename.clone());
+                (filename, true)
+            }
+        };
+        let compressed = if is_new {
+            self.write_file(&filename, bytes)?
+        } else {
+            0
+        };
+        let written = (bytes.len() as u64, compressed);
+        lock_recover(&dedup.manifest).push(ManifestEntry {
+            seq,
+            hash,
+            filename,
+        });
+        Ok(written)
+    }
+
+    /// Flush the ordered manifest once every worker has drained.
+    fn write_manifest(&self) -> Result<()> {
+        let dedup = match &self.dedup {
+            Some(dedup) => dedup,
+            None => return Ok(()),
+        };
+        let mut entries = lock_recover(&dedup.manifest);
+        entries.sort_by_key(|entry| entry.seq);
+        let path = PathBuf::from("manifest.txt");
+        let file = File::create(&path).map_err(|source| Osm2GzipError::Create {
+            path: path.clone(),
+            source,
+        })?;
+        let mut out = BufWriter::new(file);
+        for entry in entries.iter() {
+            writeln!(out, "{} {} {}", entry.seq, entry.hash.to_hex(), entry.filename).map_err(
+                |source| Osm2GzipError::Write {
+                    path: path.clone(),
+                    source,
+                },
+            )?;
+        }
+        out.flush().map_err(|source| Osm2GzipError::Write {
+            path: path.clone(),
+            source,
+        })
     }
-    let reader = BlobReader::from_path(&args[1])?;
+}
+
+/// Per-element-type counts and byte totals, accumulated per sink and merged
+/// across the pool for the final `--stats` summary.
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+    nodes: u64,
+    dense_nodes: u64,
+    ways: u64,
+    relations: u64,
+    uncompressed: u64,
+    compressed: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: &Stats) {
+        self.nodes += other.nodes;
+        self.dense_nodes += other.dense_nodes;
+        self.ways += other.ways;
+        self.relations += other.relations;
+        self.uncompressed += other.uncompressed;
+        self.compressed += other.compressed;
+    }
+}
+
+/// A decoded, serialized block handed from a decode worker to a writer. The
+/// `index` is the blob's position in the file; writers release batches into
+/// CDC in ascending `index` order so each partition's byte stream is the same
+/// on every run regardless of the nondeterministic decode order.
+#[derive(Debug, Default)]
+struct Batch {
+    index: u64,
+    bytes: Vec<u8>,
+    nodes: u64,
+    dense_nodes: u64,
+    ways: u64,
+    relations: u64,
+}
+
+impl Batch {
+    fn elements(&self) -> u64 {
+        self.nodes + self.dense_nodes + self.ways + self.relations
+    }
+}
+
+#[derive(Debug)]
+struct ElementSink {
+    writer: Arc<ChunkWriter>,
+    buffer: Vec<u8>,
+    chunker: Chunker,
+    stats: Stats,
+}
+
+impl ElementSink {
+    const MAX_ELEMENTS_COUNT: u64 = 100_000;
+
+    fn new(writer: Arc<ChunkWriter>, mode: ChunkingMode) -> Self {
+        ElementSink {
+            writer,
+            buffer: Vec::new(),
+            chunker: Chunker::from_mode(mode),
+            stats: Stats::default(),
+        }
+    }
+
+    /// Append a decoded batch to the in-memory chunk, flushing completed
+    /// chunks as the active chunking strategy signals boundaries.
+    fn write_batch(&mut self, batch: &Batch) -> Result<()> {
+        self.stats.nodes += batch.nodes;
+        self.stats.dense_nodes += batch.dense_nodes;
+        self.stats.ways += batch.ways;
+        self.stats.relations += batch.relations;
+        let bytes = &batch.bytes;
+        let elements = batch.elements();
+        match &mut self.chunker {
+            Chunker::Count(count) => {
+                *count += elements;
+                let cut = *count >= Self::MAX_ELEMENTS_COUNT;
+                self.buffer.extend_from_slice(bytes);
+                if cut {
+                    self.flush_chunk()?;
+                }
+            }
+            Chunker::ContentDefined(gear) => {
+                let cuts = gear.scan(bytes);
+                let mut start = 0;
+                for off in cuts {
+                    self.buffer.extend_from_slice(&bytes[start..off]);
+                    start = off;
+                    self.flush_chunk()?;
+                }
+                self.buffer.extend_from_slice(&bytes[start..]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand the accumulated chunk to the shared writer and reset the state.
+    fn flush_chunk(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let (uncompressed, compressed) = self.writer.emit_chunk(&self.buffer)?;
+        self.stats.uncompressed += uncompressed;
+        self.stats.compressed += compressed;
+        self.buffer.clear();
+        // Only the count-based chunker resets here; `GearChunker::scan` owns
+        // all CDC resets (it resets at each cut and carries the post-cut tail
+        // state across batches), so resetting it here would drop that tail and
+        // make boundaries depend on batch splits rather than content.
+        if let Chunker::Count(count) = &mut self.chunker {
+            *count = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Serialize every element of a decoded block into a flat byte batch,
+/// tallying the per-element-type counts along the way.
+fn serialize_block(block: &osmpbf::PrimitiveBlock) -> io::Result<Batch> {
+    let mut batch = Batch::default();
+    for elem in block.elements() {
+        match elem {
+            Element::Node(ref node) => {
+                writeln!(batch.bytes, "node {}", node.id())?;
+                batch.nodes += 1;
+            }
+            Element::DenseNode(ref node) => {
+                writeln!(batch.bytes, "node {}", node.id())?;
+                batch.dense_nodes += 1;
+            }
+            Element::Way(ref way) => {
+                writeln!(batch.bytes, "way {}", way.id())?;
+                batch.ways += 1;
+            }
+            Element::Relation(ref rel) => {
+                writeln!(batch.bytes, "relation {}", rel.id())?;
+                batch.relations += 1;
+            }
+        }
+    }
+    Ok(batch)
+}
+
+/// Print the `--stats` summary: per-type counts, byte totals, ratio and speed.
+fn print_stats(stats: &Stats, elapsed: Duration) {
+    let ratio = if stats.compressed > 0 {
+        stats.uncompressed as f64 / stats.compressed as f64
+    } else {
+        0.0
+    };
+    let secs = elapsed.as_secs_f64();
+    let mbps = if secs > 0.0 {
+        stats.uncompressed as f64 / 1_000_000.0 / secs
+    } else {
+        0.0
+    };
+    println!("nodes:             {}", stats.nodes);
+    println!("dense nodes:       {}", stats.dense_nodes);
+    println!("ways:              {}", stats.ways);
+    println!("relations:         {}", stats.relations);
+    println!("uncompressed bytes: {}", stats.uncompressed);
+    println!("compressed bytes:   {}", stats.compressed);
+    println!("compression ratio:  {:.3}", ratio);
+    println!("throughput:         {:.2} MB/s", mbps);
+}
+
+/// Parsed configuration from the command line.
+#[derive(Debug)]
+struct Config {
+    compression: CompressionConfig,
+    chunking: ChunkingMode,
+    dedup: bool,
+    stats: bool,
+    decode_threads: Option<usize>,
+    write_threads: usize,
+    queue_cap: usize,
+}
+
+/// Default target average chunk size in content-defined mode: 64 KiB.
+const DEFAULT_AVG_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Smallest accepted target average chunk size for `--avg-size`. Smaller
+/// values (and 0) are rejected, which keeps the mask shifts in
+/// [`ChunkerConfig::new`] from underflowing.
+const MIN_AVG_CHUNK_SIZE: u64 = 1024;
 
-    let sinkpool: Arc<Mutex<Vec<ElementSink>>> = Arc::new(Mutex::new(vec![]));
-    let filenum: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
+fn default_write_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+}
 
-    let get_sink_from_pool = || -> Result<ElementSink, std::io::Error> {
-        {
-            let mut pool = sinkpool.lock().unwrap();
-            if let Some(sink) = pool.pop() {
-                return Ok(sink);
+/// Parse the positional input path and the compression, chunking, dedup and
+/// pipeline-tuning flags.
+fn parse_args(args: &[String]) -> Option<(String, Config)> {
+    let mut input = None;
+    let mut compression = CompressionConfig::default();
+    let mut avg_size = DEFAULT_AVG_CHUNK_SIZE;
+    let mut content_defined = false;
+    let mut dedup = false;
+    let mut stats = false;
+    let mut decode_threads = None;
+    let mut write_threads = None;
+    let mut queue_cap = None;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--codec" => {
+                compression.codec = Codec::parse(iter.next()?)?;
+            }
+            "--level" => {
+                compression.level = Some(iter.next()?.parse().ok()?);
+            }
+            "--chunking" => match iter.next()?.as_str() {
+                "count" => content_defined = false,
+                "cdc" => content_defined = true,
+                _ => return None,
+            },
+            "--avg-size" => {
+                avg_size = iter.next()?.parse().ok()?;
+            }
+            "--dedup" => dedup = true,
+            "--stats" => stats = true,
+            "--decode-threads" => {
+                decode_threads = Some(iter.next()?.parse().ok()?);
             }
+            "--write-threads" => {
+                write_threads = Some(iter.next()?.parse().ok()?);
+            }
+            "--queue" => {
+                queue_cap = Some(iter.next()?.parse().ok()?);
+            }
+            _ => {
+                if input.is_some() {
+                    return None;
+                }
+                input = Some(arg.clone());
+            }
+        }
+    }
+    let chunking = if content_defined {
+        if avg_size < MIN_AVG_CHUNK_SIZE {
+            return None;
         }
-        ElementSink::new(filenum.clone())
+        ChunkingMode::ContentDefined(ChunkerConfig::new(avg_size))
+    } else {
+        ChunkingMode::Count
     };
+    if let Some(level) = compression.level {
+        if !compression.codec.level_range().contains(&level) {
+            return None;
+        }
+    }
+    let write_threads = write_threads.unwrap_or_else(default_write_threads).max(1);
+    let queue_cap = queue_cap.unwrap_or(write_threads * 2).max(1);
+    let config = Config {
+        compression,
+        chunking,
+        dedup,
+        stats,
+        decode_threads,
+        write_threads,
+        queue_cap,
+    };
+    input.map(|input| (input, config))
+}
 
-    let add_sink_to_pool = |sink| {
-        let mut pool = sinkpool.lock().unwrap();
-        pool.push(sink);
+fn main() {
+    if let Err(err) = run() {
+        // Surface the `Display` diagnostic (e.g. "failed to write
+        // elements_00042.txt.gz: ...") rather than the `Debug` form the `?`
+        // return in `main` would print, and fail with a nonzero status.
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let (input, config) = match parse_args(&args) {
+        Some(parsed) => parsed,
+        None => {
+            println!(
+                "Usage: osm2gzip <file.osm.pbf> [--codec gzip|zstd|brotli|lz4] [--level N] \
+                 [--chunking count|cdc] [--avg-size BYTES] [--dedup] [--stats] \
+                 [--decode-threads N] [--write-threads N] [--queue N]"
+            );
+            return Ok(());
+        }
     };
+    let reader = BlobReader::from_path(&input)?;
 
-    reader
-        .par_bridge()
-        .try_for_each(|blob| -> anyhow::Result<()> {
-            if let BlobDecode::OsmData(block) = blob?.decode()? {
-                let mut sink = get_sink_from_pool()?;
-                for elem in block.elements() {
-                    match elem {
-                        Element::Node(ref node) => {
-                            sink.add_node(node)?;
-                        }
-                        Element::DenseNode(ref node) => {
-                            sink.add_dense_node(node)?;
-                        }
-                        Element::Way(ref way) => {
-                            sink.add_way(way)?;
-                        }
-                        Element::Relation(ref rel) => {
-                            sink.add_relation(rel)?;
-                        }
-                    }
+    let chunking = config.chunking;
+    let writer = Arc::new(ChunkWriter::new(config.compression, config.dedup));
+    let start = Instant::now();
+
+    // One bounded queue per writer. Blobs are routed to a fixed partition by
+    // their file index, so a given blob always reaches the same sink and each
+    // sink owns a deterministic slice of the file. Backpressure from the
+    // bounded channels caps the batches queued per writer; batches a writer
+    // holds back to restore index order (below) are not bounded by it.
+    let partitions = config.write_threads as u64;
+    let mut txs = Vec::with_capacity(config.write_threads);
+    let mut rxs = Vec::with_capacity(config.write_threads);
+    for _ in 0..config.write_threads {
+        let (tx, rx) = bounded::<Batch>(config.queue_cap);
+        txs.push(tx);
+        rxs.push(rx);
+    }
+    let mut writers = Vec::with_capacity(config.write_threads);
+    for (partition, rx) in rxs.into_iter().enumerate() {
+        let writer = writer.clone();
+        let partition = partition as u64;
+        writers.push(std::thread::spawn(move || -> Result<Stats> {
+            let mut sink = ElementSink::new(writer, chunking);
+            // Release batches into CDC strictly in ascending blob-index order
+            // (this partition owns indices `partition`, `partition + N`, ...),
+            // buffering out-of-order arrivals so the byte stream is stable.
+            let mut pending: BTreeMap<u64, Batch> = BTreeMap::new();
+            let mut next = partition;
+            for batch in rx.iter() {
+                pending.insert(batch.index, batch);
+                while let Some(batch) = pending.remove(&next) {
+                    sink.write_batch(&batch)?;
+                    next += partitions;
                 }
-                add_sink_to_pool(sink);
             }
-            Ok(())
-        })?;
+            // Flush whatever is left if decode aborted mid-stream, in order.
+            for (_, batch) in pending {
+                sink.write_batch(&batch)?;
+            }
+            sink.flush_chunk()?;
+            Ok(sink.stats)
+        }));
+    }
 
-    {
-        let mut pool = sinkpool.lock().unwrap();
-        for sink in pool.drain(..) {
-            sink.writer.finish()?.flush()?;
+    // Decode blobs in parallel, bounded by `decode_threads`, routing each to
+    // its partition. Non-data blobs (e.g. the header) carry no elements but
+    // still occupy an index so every partition's stream stays gap-free.
+    let decode = |txs: &[Sender<Batch>]| -> Result<()> {
+        reader
+            .enumerate()
+            .par_bridge()
+            .try_for_each(|(index, blob)| -> Result<()> {
+                let index = index as u64;
+                let batch = match blob?.decode()? {
+                    BlobDecode::OsmData(block) => {
+                        let mut batch = serialize_block(&block).map_err(|err| {
+                            Osm2GzipError::Pipeline(format!("failed to serialize block: {}", err))
+                        })?;
+                        batch.index = index;
+                        batch
+                    }
+                    _ => Batch {
+                        index,
+                        ..Default::default()
+                    },
+                };
+                txs[(index % partitions) as usize]
+                    .send(batch)
+                    .map_err(|_| Osm2GzipError::Pipeline("writer threads stopped".into()))?;
+                Ok(())
+            })
+    };
+    let decode_result = match config.decode_threads {
+        Some(threads) => ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()?
+            .install(|| decode(&txs)),
+        None => decode(&txs),
+    };
+    drop(txs);
+
+    // Drain the writers, merging their stats and surfacing the first error.
+    let mut result = decode_result;
+    let mut stats = Stats::default();
+    for handle in writers {
+        match handle.join() {
+            Ok(Ok(worker_stats)) => stats.merge(&worker_stats),
+            Ok(Err(err)) if result.is_ok() => result = Err(err),
+            Ok(Err(_)) => {}
+            Err(_) if result.is_ok() => {
+                result = Err(Osm2GzipError::Pipeline(
+                    "a writer thread panicked".into(),
+                ))
+            }
+            Err(_) => {}
         }
     }
+    result?;
+
+    writer.write_manifest()?;
+    if config.stats {
+        print_stats(&stats, start.elapsed());
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_parse_accepts_aliases_and_rejects_unknown() {
+        assert_eq!(Codec::parse("gzip"), Some(Codec::Gzip));
+        assert_eq!(Codec::parse("gz"), Some(Codec::Gzip));
+        assert_eq!(Codec::parse("zstd"), Some(Codec::Zstd));
+        assert_eq!(Codec::parse("br"), Some(Codec::Brotli));
+        assert_eq!(Codec::parse("lz4"), Some(Codec::Lz4));
+        assert_eq!(Codec::parse("xz"), None);
+    }
+
+    #[test]
+    fn chunker_config_masks_track_the_average_size() {
+        // avg_size == 2^16, so avg_bits == 16: the strict mask carries two more
+        // set bits than the average and the loose mask two fewer.
+        let config = ChunkerConfig::new(64 * 1024);
+        assert_eq!(config.mask_s.count_ones(), 18);
+        assert_eq!(config.mask_l.count_ones(), 14);
+        assert_eq!(config.min_size, 16 * 1024);
+        assert_eq!(config.max_size, 256 * 1024);
+    }
+
+    /// Absolute cut offsets produced when `data` is fed to one chunker as
+    /// consecutive `piece`-byte slices.
+    fn cuts_in_pieces(config: ChunkerConfig, data: &[u8], piece: usize) -> Vec<usize> {
+        let mut gear = GearChunker::new(config);
+        let mut cuts = Vec::new();
+        let mut base = 0;
+        for window in data.chunks(piece) {
+            for off in gear.scan(window) {
+                cuts.push(base + off);
+            }
+            base += window.len();
+        }
+        cuts
+    }
+
+    #[test]
+    fn scan_boundaries_are_independent_of_batch_splits() {
+        let config = ChunkerConfig::new(1024);
+        // A deterministic pseudo-random stream long enough for several cuts.
+        let mut data = Vec::with_capacity(200_000);
+        let mut x: u32 = 0x1234_5678;
+        for _ in 0..200_000 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            data.push((x & 0xff) as u8);
+        }
+        let whole = cuts_in_pieces(config, &data, data.len());
+        assert!(whole.len() > 1, "expected several content-defined cuts");
+        for piece in [1usize, 7, 64, 333, 4096] {
+            assert_eq!(
+                cuts_in_pieces(config, &data, piece),
+                whole,
+                "cut offsets changed when fed in {}-byte pieces",
+                piece
+            );
+        }
+    }
+}